@@ -5,11 +5,13 @@ use core::convert::Infallible;
 use embedded_hal::digital::v2::{InputPin, OutputPin};
 use generic_array::typenum::{U12, U5};
 use keyberon::action::Action::{self, *};
-use keyberon::action::{k, l, m};
+use keyberon::action::{k, l, m, HoldTapConfig};
 use keyberon::debounce::Debouncer;
 use keyberon::impl_heterogenous_array;
 use keyberon::key_code::KeyCode::*;
-use keyberon::key_code::{KbHidReport, KeyCode};
+use keyberon::key_code::{
+    ConsumerReport, KbHidReport, KeyCode, NkroHidReport, SystemControlReport,
+};
 use keyberon::layout::Layout;
 use keyberon::matrix::{Matrix, PressedKeys};
 use panic_halt as _;
@@ -22,6 +24,7 @@ use usb_device::bus::UsbBusAllocator;
 use usb_device::class::UsbClass as _;
 
 type UsbClass = keyberon::Class<'static, UsbBusType, Leds>;
+type ConsumerClass = keyberon::ConsumerClass<'static, UsbBusType>;
 type UsbDevice = usb_device::device::UsbDevice<'static, UsbBusType>;
 
 pub struct Leds {
@@ -79,11 +82,20 @@ const L2_ENTER: Action = HoldTap {
     timeout: 160,
     hold: &l(2),
     tap: &k(Enter),
+    config: HoldTapConfig::DEFAULT,
 };
 const L1_SP: Action = HoldTap {
     timeout: 200,
     hold: &l(1),
     tap: &k(Space),
+    config: HoldTapConfig {
+        quick_tap: 200,
+        ..HoldTapConfig::DEFAULT
+    },
+};
+const OS_SHIFT: Action = OneShot {
+    timeout: 1000,
+    action: &k(RShift),
 };
 
 #[rustfmt::skip]
@@ -93,7 +105,7 @@ pub static LAYERS: keyberon::layout::Layers = &[
         &[k(Tab),     k(Q), k(W),  k(E),    k(R), k(T),    k(Y),     k(U),    k(I),   k(O),    k(P),     k(LBracket)],
         &[k(RBracket),k(A), k(S),  k(D),    k(F), k(G),    k(H),     k(J),    k(K),   k(L),    k(SColon),k(Quote)   ],
         &[k(Equal),   k(Z), k(X),  k(C),    k(V), k(B),    k(N),     k(M),    k(Comma),k(Dot), k(Slash), k(Bslash)  ],
-        &[Trans,      Trans,k(LGui),k(LAlt),L1_SP,k(LCtrl),k(RShift),L2_ENTER,k(RAlt),k(BSpace),Trans,   Trans      ],
+        &[Trans,      Trans,k(LGui),k(LAlt),L1_SP,k(LCtrl),OS_SHIFT, L2_ENTER,k(RAlt),k(BSpace),Trans,   ToggleNkro ],
     ]
 ];
 
@@ -102,6 +114,7 @@ const APP: () = {
     struct Resources {
         usb_dev: UsbDevice,
         usb_class: UsbClass,
+        consumer_class: ConsumerClass,
         matrix: Matrix<Cols, Rows>,
         debouncer: Debouncer<PressedKeys<U5, U12>>,
         layout: Layout,
@@ -155,6 +168,7 @@ const APP: () = {
         let usb_bus = USB_BUS.as_ref().unwrap();
 
         let usb_class = keyberon::new_class(usb_bus, leds);
+        let consumer_class = keyberon::new_consumer_class(usb_bus);
         let usb_dev = keyberon::new_device(usb_bus);
 
         let mut timer =
@@ -188,6 +202,7 @@ const APP: () = {
         init::LateResources {
             usb_dev,
             usb_class,
+            consumer_class,
             timer,
             debouncer: Debouncer::new(PressedKeys::default(), PressedKeys::default(), 5),
             matrix: matrix.unwrap(),
@@ -195,41 +210,88 @@ const APP: () = {
         }
     }
 
-    #[task(binds = USB_HP_CAN_TX, priority = 2, resources = [usb_dev, usb_class])]
-    fn usb_tx(mut c: usb_tx::Context) {
-        usb_poll(&mut c.resources.usb_dev, &mut c.resources.usb_class);
+    #[task(binds = USB_HP_CAN_TX, priority = 2, resources = [usb_dev, usb_class, consumer_class])]
+    fn usb_tx(c: usb_tx::Context) {
+        usb_poll(
+            c.resources.usb_dev,
+            c.resources.usb_class,
+            c.resources.consumer_class,
+        );
     }
 
-    #[task(binds = USB_LP_CAN_RX0, priority = 2, resources = [usb_dev, usb_class])]
-    fn usb_rx(mut c: usb_rx::Context) {
-        usb_poll(&mut c.resources.usb_dev, &mut c.resources.usb_class);
+    #[task(binds = USB_LP_CAN_RX0, priority = 2, resources = [usb_dev, usb_class, consumer_class])]
+    fn usb_rx(c: usb_rx::Context) {
+        usb_poll(
+            c.resources.usb_dev,
+            c.resources.usb_class,
+            c.resources.consumer_class,
+        );
     }
 
-    #[task(binds = TIM3, priority = 1, resources = [usb_class, matrix, debouncer, layout, timer])]
+    #[task(binds = TIM3, priority = 1, resources = [usb_class, consumer_class, matrix, debouncer, layout, timer])]
     fn tick(mut c: tick::Context) {
         c.resources.timer.clear_update_interrupt_flag();
+        c.resources.usb_class.lock(|k| k.tick());
+        c.resources.consumer_class.lock(|k| k.tick());
 
         for event in c
             .resources
             .debouncer
             .events(c.resources.matrix.get().unwrap())
         {
-            send_report(c.resources.layout.event(event), &mut c.resources.usb_class);
+            send_report(
+                c.resources.layout.event(event),
+                &mut c.resources.usb_class,
+                &mut c.resources.consumer_class,
+            );
         }
-        send_report(c.resources.layout.tick(), &mut c.resources.usb_class);
+        let nkro = c.resources.layout.nkro();
+        c.resources
+            .usb_class
+            .lock(|k| k.device_mut().set_nkro(nkro));
+        send_report(
+            c.resources.layout.tick(),
+            &mut c.resources.usb_class,
+            &mut c.resources.consumer_class,
+        );
     }
 };
 
-fn send_report(iter: impl Iterator<Item = KeyCode>, usb_class: &mut resources::usb_class<'_>) {
+fn send_report(
+    iter: impl Iterator<Item = KeyCode>,
+    usb_class: &mut resources::usb_class<'_>,
+    consumer_class: &mut resources::consumer_class<'_>,
+) {
     use rtic::Mutex;
-    let report: KbHidReport = iter.collect();
-    if usb_class.lock(|k| k.device_mut().set_keyboard_report(report.clone())) {
-        while let Ok(0) = usb_class.lock(|k| k.write(report.as_bytes())) {}
+    let mut report = KbHidReport::default();
+    let mut nkro_report = NkroHidReport::default();
+    let mut consumer_report = ConsumerReport::default();
+    let mut system_report = SystemControlReport::default();
+    for kc in iter {
+        report.pressed(kc);
+        nkro_report.pressed(kc);
+        consumer_report.pressed(kc);
+        system_report.pressed(kc);
+    }
+    // The reports are written as returned by the keyboard, thus in
+    // the protocol used at the time of the write.
+    for &report_id in usb_class.lock(|k| k.device_mut().set_reports(report, nkro_report)) {
+        while let Ok(0) = usb_class.lock(|k| k.write_report(report_id)) {}
+    }
+    if consumer_class.lock(|k| k.device_mut().set_consumer_report(consumer_report.clone())) {
+        while let Ok(0) = consumer_class.lock(|k| k.write(consumer_report.as_bytes())) {}
+    }
+    if consumer_class.lock(|k| {
+        k.device_mut()
+            .set_system_control_report(system_report.clone())
+    }) {
+        while let Ok(0) = consumer_class.lock(|k| k.write(system_report.as_bytes())) {}
     }
 }
 
-fn usb_poll(usb_dev: &mut UsbDevice, keyboard: &mut UsbClass) {
-    if usb_dev.poll(&mut [keyboard]) {
+fn usb_poll(usb_dev: &mut UsbDevice, keyboard: &mut UsbClass, consumer: &mut ConsumerClass) {
+    if usb_dev.poll(&mut [keyboard, consumer]) {
         keyboard.poll();
+        consumer.poll();
     }
 }